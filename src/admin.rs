@@ -0,0 +1,51 @@
+//! Authorization of admin commands against a Telegram group's admin list,
+//! so a whole team can operate the bot instead of one hardcoded username.
+
+use std::{
+    collections::HashSet,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use telegram_bot::{TelegramBot, UserId};
+
+use crate::CONFIG;
+
+/// Caches the administrator list of the configured control group for a
+/// short TTL, so authorizing a command doesn't hit Telegram's API on every
+/// message.
+pub struct AdminCache {
+    ttl: Duration,
+    cached: Mutex<Option<(Instant, HashSet<UserId>)>>,
+}
+
+impl AdminCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Whether `user_id` is an administrator of the control group
+    /// (`Config::control_group_id`). Callers should also accept
+    /// `Config::admin_uname` as a fallback owner override.
+    pub async fn is_group_admin(&self, telegram: &TelegramBot, user_id: UserId) -> anyhow::Result<bool> {
+        Ok(self.admins(telegram).await?.contains(&user_id))
+    }
+
+    async fn admins(&self, telegram: &TelegramBot) -> anyhow::Result<HashSet<UserId>> {
+        if let Some((fetched_at, admins)) = self.cached.lock().unwrap().clone() {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(admins);
+            }
+        }
+        let admins: HashSet<UserId> = telegram
+            .get_chat_administrators(CONFIG.control_group_id)
+            .await?
+            .into_iter()
+            .collect();
+        *self.cached.lock().unwrap() = Some((Instant::now(), admins.clone()));
+        Ok(admins)
+    }
+}
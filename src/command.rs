@@ -0,0 +1,205 @@
+//! Parsing of admin/entrant messages into a typed [`Command`], kept separate
+//! from Telegram I/O so it can be unit-tested on its own.
+
+/// A command parsed out of a private-chat message sent to the bot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `#StartRaffle [key]` followed by a required `#SecretCode <code>`
+    /// line and one giftcard code per remaining line. The secret code is
+    /// mandatory: it's the only thing that tells an entrant's message apart
+    /// from every other concurrently running raffle.
+    StartRaffle {
+        raffle_key: Option<String>,
+        secret_code: String,
+        giftcards: Vec<String>,
+    },
+    /// `#EndRaffle [key]`.
+    EndRaffle { raffle_key: Option<String> },
+    /// `#ParticipantsCount [key]`.
+    ParticipantsCount { raffle_key: Option<String> },
+    /// `#GiftcardsCount [key]`.
+    GiftcardsCount { raffle_key: Option<String> },
+    /// Anything else sent in a private chat: an entrant supplying the
+    /// secret code of the raffle they want to join.
+    Enter { code: String },
+    /// A recognized admin command that was malformed in some way. Carries a
+    /// human-readable reason to send back to the admin.
+    Invalid { reason: String },
+}
+
+/// Whether `word` looks like a giftcard code (repo convention:
+/// all-uppercase/numeric, longer than 5 chars); used to filter out
+/// anything else.
+fn looks_like_giftcard(word: &str) -> bool {
+    word.chars().all(|c| c.is_uppercase() || c.is_numeric()) && word.len() > 5
+}
+
+/// Trims a command argument, mapping an empty remainder to `None` so
+/// callers can fall back to the shared default raffle key (see
+/// `crate::resolve_raffle_key`).
+fn raffle_key_arg(rest: &str) -> Option<String> {
+    let rest = rest.trim();
+    (!rest.is_empty()).then(|| rest.to_string())
+}
+
+/// Parses a single private-chat message into a [`Command`], or `None` if the
+/// message is empty.
+pub fn parse_command(msg: &str) -> Option<Command> {
+    let mut lines = msg.split_terminator('\n');
+    let first_line = lines.next()?;
+
+    if let Some(rest) = first_line.strip_prefix("#StartRaffle") {
+        let raffle_key = raffle_key_arg(rest);
+
+        let mut lines = lines.peekable();
+        let secret_code = match lines.peek().copied() {
+            Some(line) if line.starts_with("#SecretCode") => {
+                lines.next();
+                let code = line.trim_start_matches("#SecretCode").trim();
+                if code.is_empty() {
+                    return Some(Command::Invalid {
+                        reason: "#SecretCode was given but had no code after it".into(),
+                    });
+                }
+                code.to_string()
+            }
+            _ => {
+                return Some(Command::Invalid {
+                    reason: "#StartRaffle needs a #SecretCode line so entrants can be routed to it"
+                        .into(),
+                });
+            }
+        };
+
+        let giftcards: Vec<String> = lines.filter(|w| looks_like_giftcard(w)).map(str::to_string).collect();
+        if giftcards.is_empty() {
+            return Some(Command::Invalid {
+                reason: "#StartRaffle needs at least one giftcard code, one per line".into(),
+            });
+        }
+
+        return Some(Command::StartRaffle {
+            raffle_key,
+            secret_code,
+            giftcards,
+        });
+    }
+    if let Some(rest) = first_line.strip_prefix("#EndRaffle") {
+        return Some(Command::EndRaffle {
+            raffle_key: raffle_key_arg(rest),
+        });
+    }
+    if let Some(rest) = first_line.strip_prefix("#ParticipantsCount") {
+        return Some(Command::ParticipantsCount {
+            raffle_key: raffle_key_arg(rest),
+        });
+    }
+    if let Some(rest) = first_line.strip_prefix("#GiftcardsCount") {
+        return Some(Command::GiftcardsCount {
+            raffle_key: raffle_key_arg(rest),
+        });
+    }
+
+    Some(Command::Enter { code: msg.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_start_raffle_with_key_and_secret_code() {
+        let msg = "#StartRaffle my-group\n#SecretCode hunter2\nABCDEFG\nHIJKLMN";
+        assert_eq!(
+            parse_command(msg),
+            Some(Command::StartRaffle {
+                raffle_key: Some("my-group".into()),
+                secret_code: "hunter2".into(),
+                giftcards: vec!["ABCDEFG".into(), "HIJKLMN".into()],
+            })
+        );
+    }
+
+    #[test]
+    fn parses_start_raffle_without_key() {
+        let msg = "#StartRaffle\n#SecretCode hunter2\nABCDEFG";
+        assert_eq!(
+            parse_command(msg),
+            Some(Command::StartRaffle {
+                raffle_key: None,
+                secret_code: "hunter2".into(),
+                giftcards: vec!["ABCDEFG".into()],
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_start_raffle_with_no_secret_code() {
+        assert_eq!(
+            parse_command("#StartRaffle\nABCDEFG"),
+            Some(Command::Invalid {
+                reason: "#StartRaffle needs a #SecretCode line so entrants can be routed to it"
+                    .into(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_start_raffle_with_no_giftcards() {
+        assert_eq!(
+            parse_command("#StartRaffle my-group\n#SecretCode hunter2"),
+            Some(Command::Invalid {
+                reason: "#StartRaffle needs at least one giftcard code, one per line".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_empty_secret_code() {
+        let msg = "#StartRaffle\n#SecretCode \nABCDEFG";
+        assert_eq!(
+            parse_command(msg),
+            Some(Command::Invalid {
+                reason: "#SecretCode was given but had no code after it".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_end_raffle_with_and_without_key() {
+        assert_eq!(
+            parse_command("#EndRaffle"),
+            Some(Command::EndRaffle { raffle_key: None })
+        );
+        assert_eq!(
+            parse_command("#EndRaffle my-group"),
+            Some(Command::EndRaffle {
+                raffle_key: Some("my-group".into())
+            })
+        );
+    }
+
+    #[test]
+    fn parses_counts() {
+        assert_eq!(
+            parse_command("#ParticipantsCount"),
+            Some(Command::ParticipantsCount { raffle_key: None })
+        );
+        assert_eq!(
+            parse_command("#GiftcardsCount my-group"),
+            Some(Command::GiftcardsCount {
+                raffle_key: Some("my-group".into())
+            })
+        );
+    }
+
+    #[test]
+    fn anything_else_is_an_entry_attempt() {
+        assert_eq!(
+            parse_command("hunter2"),
+            Some(Command::Enter {
+                code: "hunter2".into()
+            })
+        );
+    }
+}
@@ -1,18 +1,27 @@
 use std::{
-    collections::BTreeSet,
+    collections::HashMap,
     path::{Path, PathBuf},
     time::Duration,
 };
 
-use acidjson::AcidJson;
 use anyhow::Context;
 use argh::FromArgs;
 use once_cell::sync::Lazy;
-use rand::{seq::SliceRandom, thread_rng};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use smol_timeout::TimeoutExt;
-use telegram_bot::{Response, TelegramBot};
+use telegram_bot::{Response, TelegramBot, UserId};
+
+mod admin;
+use admin::AdminCache;
+
+mod command;
+use command::{parse_command, Command};
+
+mod messages;
+
+mod storage;
+use storage::{PendingAward, Storage, StoreBackend};
 
 /// raffle bot
 #[derive(FromArgs, PartialEq, Debug)]
@@ -27,8 +36,30 @@ struct Args {
 struct Config {
     store_path: String,
     telegram_token: String,
+    /// Fallback/owner override: this username can always run admin
+    /// commands, even if they aren't in `control_group_id`'s admin list.
     admin_uname: String,
     bot_uname: String,
+    /// The group chat whose administrators are authorized to run
+    /// `#StartRaffle`/`#EndRaffle`/etc.
+    control_group_id: i64,
+    /// How long the control group's admin list is cached for, to avoid
+    /// hammering `getChatAdministrators`.
+    #[serde(default = "default_admin_cache_ttl_secs")]
+    admin_cache_ttl_secs: u64,
+    /// Which `Storage` implementation to persist raffle state with.
+    /// Defaults to the original single-file AcidJson store.
+    #[serde(default)]
+    store_backend: StoreBackend,
+    /// Operator overrides for user-facing message templates, keyed by
+    /// message name (e.g. `won_giftcard`). Unset keys fall back to the
+    /// built-in defaults; see `messages::render`.
+    #[serde(default)]
+    messages: HashMap<String, String>,
+}
+
+fn default_admin_cache_ttl_secs() -> u64 {
+    60
 }
 
 static ARGS: Lazy<Args> = Lazy::new(argh::from_env);
@@ -38,68 +69,109 @@ static CONFIG: Lazy<Config> = Lazy::new(|| {
     serde_yaml::from_slice(s).expect("cannot parse config file")
 });
 
-#[derive(Serialize, Deserialize, Clone)]
-struct Store {
-    giftcards: BTreeSet<String>,
-    participants: BTreeSet<i64>, // list of all chat ids
-    secret_code: Option<String>,
-}
+/// A key identifying one raffle among potentially many concurrent ones:
+/// the name an admin gives after the command, or [`DEFAULT_RAFFLE_KEY`]
+/// when none is given.
+pub type RaffleKey = String;
 
-async fn send_giftcards() {
-    // shuffle participants list
-    let mut store = STORE.read().clone();
-    let mut participants: Vec<i64> = store.participants.iter().copied().collect();
-    participants.shuffle(&mut thread_rng());
-    for chat_id in participants {
-        if let Some(gc) = store.giftcards.pop_first() {
-            let fallible = async {
-                TELEGRAM
-                    .send_msg(Response {
-                        text: "Congratulations! You won a giftcard 🎁 The code is:".into(),
-                        chat_id,
-                        reply_to_message_id: None,
-                    })
-                    .timeout(Duration::from_secs(10))
-                    .await
-                    .context("timeout")??;
-                TELEGRAM
-                    .send_msg(Response {
-                        text: gc,
-                        chat_id,
-                        reply_to_message_id: None,
-                    })
-                    .await?;
-                anyhow::Ok(())
-            };
-            if let Err(err) = fallible.await {
-                eprintln!("error giving out a giftcard to {chat_id}: {:?}", err);
-            } else {
-                eprintln!("gave out a giftcard to {chat_id}");
-                STORE.write().participants.remove(&chat_id);
-                eprintln!("removed {chat_id} from participants");
+/// The default raffle key used when the admin doesn't name one explicitly.
+/// Deliberately a single shared literal: chunk0-5 lets a whole team of
+/// control-group admins operate the bot, and an unkeyed command is meant to
+/// target the raffle the team is collectively running. An admin who wants a
+/// second, independent raffle running concurrently must name an explicit
+/// key for it.
+const DEFAULT_RAFFLE_KEY: &str = "default";
+
+/// Delivers one pending award's giftcard, marking it delivered only once
+/// the message actually goes out. Can be called again for an award that
+/// was never confirmed delivered (e.g. after a crash): it re-sends the same
+/// giftcard to the same chat, and the delivered mark is keyed by
+/// `(raffle_key, chat_id)`. If the process stops after the message goes out
+/// but before the award is marked delivered, the next attempt sends it again.
+async fn deliver_award(award: PendingAward) {
+    let mut ctx = tera::Context::new();
+    ctx.insert("giftcard", &award.giftcard);
+    let text = messages::render("won_giftcard", &ctx);
+    let fallible = async {
+        TELEGRAM
+            .send_msg(Response {
+                text,
+                chat_id: award.chat_id,
+                reply_to_message_id: None,
+            })
+            .timeout(Duration::from_secs(10))
+            .await
+            .context("timeout")??;
+        anyhow::Ok(())
+    };
+    match fallible.await {
+        Err(err) => eprintln!(
+            "error delivering giftcard to {} for raffle {}: {:?}",
+            award.chat_id, award.raffle_key, err
+        ),
+        Ok(()) => {
+            eprintln!("gave out a giftcard to {}", award.chat_id);
+            if let Err(err) = STORE.mark_delivered(&award.raffle_key, award.chat_id) {
+                eprintln!("could not mark award to {} delivered: {err:?}", award.chat_id);
             }
-            smol::Timer::after(Duration::from_millis(200)).await;
         }
     }
-    STORE.write().participants.clear();
-    STORE.write().giftcards.clear();
+    smol::Timer::after(Duration::from_millis(200)).await;
+}
+
+/// Ends a raffle: pairs every remaining participant with a giftcard as a
+/// persisted, undelivered award (so a crash here can't double-issue or
+/// lose a card), delivers every undelivered award for the raffle -
+/// including any left over from a previous crashed attempt - and only then
+/// clears the raffle's leftover (unpaired) participants/giftcards. This
+/// makes `#EndRaffle` safe to retry.
+async fn send_giftcards(raffle_key: &str) {
+    let raffle_key = raffle_key.to_string();
+    if let Err(err) = STORE.pair_awards(&raffle_key) {
+        eprintln!("could not pair awards for raffle {raffle_key}: {err:?}");
+    }
+    let awards = STORE.pending_awards(&raffle_key).unwrap_or_else(|err| {
+        eprintln!("could not list pending awards for raffle {raffle_key}: {err:?}");
+        Vec::new()
+    });
+    for award in awards {
+        deliver_award(award).await;
+    }
+    if let Err(err) = STORE.clear_raffle(&raffle_key) {
+        eprintln!("could not clear raffle {raffle_key}: {err:?}");
+    }
+}
+
+/// Resumes delivery of any giftcard awards that were paired but never
+/// confirmed delivered before the process last stopped.
+async fn resume_pending_awards() {
+    let pending = STORE.all_pending_awards().unwrap_or_else(|err| {
+        eprintln!("could not list pending awards to resume: {err:?}");
+        Vec::new()
+    });
+    for award in pending {
+        eprintln!(
+            "resuming undelivered award to {} for raffle {}",
+            award.chat_id, award.raffle_key
+        );
+        deliver_award(award).await;
+    }
 }
 
-static STORE: Lazy<AcidJson<Store>> = Lazy::new(|| {
-    AcidJson::open_or_else(Path::new(&CONFIG.store_path), || Store {
-        giftcards: BTreeSet::new(),
-        participants: BTreeSet::new(),
-        secret_code: None,
-    })
-    .unwrap()
+static STORE: Lazy<Box<dyn Storage>> = Lazy::new(|| {
+    storage::open(CONFIG.store_backend, Path::new(&CONFIG.store_path)).expect("cannot open store")
 });
 
 static TELEGRAM: Lazy<TelegramBot> =
     Lazy::new(|| TelegramBot::new(&CONFIG.telegram_token, telegram_msg_handler));
 
-#[derive(Deserialize, Serialize)]
-struct StartRaffle {
-    giftcards: Vec<String>,
+static ADMIN_CACHE: Lazy<AdminCache> =
+    Lazy::new(|| AdminCache::new(Duration::from_secs(CONFIG.admin_cache_ttl_secs)));
+
+/// Resolves a command's optional raffle key to a concrete [`RaffleKey`],
+/// defaulting to [`DEFAULT_RAFFLE_KEY`] when the admin didn't name one.
+fn resolve_raffle_key(raffle_key: Option<String>) -> RaffleKey {
+    raffle_key.unwrap_or_else(|| DEFAULT_RAFFLE_KEY.to_string())
 }
 
 async fn telegram_msg_handler(update: Value) -> anyhow::Result<Vec<Response>> {
@@ -113,55 +185,93 @@ async fn telegram_msg_handler(update: Value) -> anyhow::Result<Vec<Response>> {
         if let Some(uname) = update["message"]["from"]["username"].as_str() {
             username = uname;
         };
+        let raw_sender_id = update["message"]["from"]["id"].as_i64();
+        let user_id = raw_sender_id.map(UserId::from);
+
+        let Some(command) = parse_command(msg) else {
+            anyhow::bail!("not responding to this case");
+        };
 
-        if username == admin_uname {
-            // start raffle
-            if msg.starts_with("#StartRaffle") {
-                let mut store = STORE.write();
-                store.giftcards.clear();
-                let mut lines = msg.split_terminator('\n').skip(1);
-                let secret_code = lines.next().filter(|code| code.starts_with("#SecretCode"));
-                eprintln!("secret code = {secret_code:?}");
-                store.secret_code = secret_code.map(|code| code.replace("#SecretCode ", ""));
-                for word in lines {
-                    if word.chars().all(|c| c.is_uppercase() || c.is_numeric()) && word.len() > 5 {
-                        eprintln!("inserting {word} into giftcard store!");
-                        store.giftcards.insert(word.to_string());
+        let is_admin = username == admin_uname
+            || match user_id {
+                Some(user_id) => ADMIN_CACHE
+                    .is_group_admin(&TELEGRAM, user_id)
+                    .await
+                    .unwrap_or_else(|err| {
+                        eprintln!("could not check group admin status for {user_id:?}: {err:?}");
+                        false
+                    }),
+                None => false,
+            };
+
+        if is_admin {
+            match command {
+                Command::StartRaffle {
+                    raffle_key,
+                    secret_code,
+                    giftcards,
+                } => {
+                    let raffle_key = resolve_raffle_key(raffle_key);
+                    STORE.clear_raffle(&raffle_key)?;
+                    eprintln!("secret code = {secret_code:?}");
+                    STORE.set_secret_code(&raffle_key, Some(secret_code.clone()))?;
+                    eprintln!(
+                        "inserting {} giftcards into giftcard store for raffle {raffle_key}!",
+                        giftcards.len()
+                    );
+                    STORE.add_giftcards(&raffle_key, &giftcards)?;
+                    let mut ctx = tera::Context::new();
+                    ctx.insert("secret_code", &secret_code);
+                    return to_response(&messages::render("raffle_started", &ctx), update);
+                }
+                Command::EndRaffle { raffle_key } => {
+                    let raffle_key = resolve_raffle_key(raffle_key);
+                    // A raffle with nothing paired and nothing left to pair
+                    // is most likely the wrong key (e.g. another admin's
+                    // raffle is the one actually running) - say so instead
+                    // of silently declaring victory over an empty raffle.
+                    if STORE.participant_count(&raffle_key)? == 0
+                        && STORE.giftcard_count(&raffle_key)? == 0
+                        && STORE.pending_awards(&raffle_key)?.is_empty()
+                    {
+                        return to_response(&messages::render("raffle_empty", &tera::Context::new()), update);
                     }
+                    send_giftcards(&raffle_key).await;
+                    return to_response(&messages::render("raffle_ended", &tera::Context::new()), update);
                 }
-                return to_response("Raffle started", update);
-            }
-            // end raffle
-            else if msg == "#EndRaffle" {
-                send_giftcards().await;
-                return to_response("Horray! We gave out all the gift cards!", update);
-            }
-            // display participants count
-            else if msg == "#ParticipantsCount" {
-                let count = STORE.read().participants.len();
-                return to_response(&count.to_string(), update);
+                Command::ParticipantsCount { raffle_key } => {
+                    let raffle_key = resolve_raffle_key(raffle_key);
+                    let count = STORE.participant_count(&raffle_key)?;
+                    return to_response(&count.to_string(), update);
+                }
+                Command::GiftcardsCount { raffle_key } => {
+                    let raffle_key = resolve_raffle_key(raffle_key);
+                    let count = STORE.giftcard_count(&raffle_key)?;
+                    return to_response(&count.to_string(), update);
+                }
+                Command::Invalid { reason } => {
+                    let mut ctx = tera::Context::new();
+                    ctx.insert("reason", &reason);
+                    return to_response(&messages::render("invalid_command", &ctx), update);
+                }
+                Command::Enter { .. } => {}
             }
-            // display giftcards count
-            else if msg == "#GiftcardsCount" {
-                let count = STORE.read().giftcards.len();
-                return to_response(&count.to_string(), update);
+        } else if let Command::Enter { code } = command {
+            if !STORE.any_active_raffle()? {
+                // no ongoing raffle
+                return to_response(&messages::render("no_raffle", &tera::Context::new()), update);
             }
-        } else if STORE.read().giftcards.is_empty() {
-            // no ongoing raffle
-            return to_response("Sorry! There's no ongoing raffle at the moment. Watch out for future raffles in our user group!", update);
-        } else {
-            // exists ongoing raffle
+            // exists ongoing raffle; the secret code tells us which one
             let chat_id = update["message"]["chat"]["id"]
                 .as_i64()
                 .context("could not get chat id")?;
-            let mut store = STORE.write();
-            if let Some(secret_code) = &store.secret_code {
-                if !msg.contains(secret_code) {
-                    return to_response("⛔ Incorrect secret code! Please provide the correct code to enter the raffle 🔑", update);
-                }
-            }
-            store.participants.insert(chat_id);
-            return to_response("🎉 Yay! You've been entered into the raffle!", update);
+            let Some(raffle_key) = STORE.raffle_matching_code(&code)? else {
+                return to_response(&messages::render("wrong_code", &tera::Context::new()), update);
+            };
+            let participant_count = STORE.add_participant(&raffle_key, chat_id)?;
+            let mut ctx = tera::Context::new();
+            ctx.insert("participant_count", &participant_count);
+            return to_response(&messages::render("entered_raffle", &ctx), update);
         }
     }
     anyhow::bail!("not responding to this case")
@@ -178,6 +288,7 @@ fn to_response(text: &str, responding_to: Value) -> anyhow::Result<Vec<Response>
 }
 
 fn main() {
+    smol::block_on(resume_pending_awards());
     Lazy::force(&TELEGRAM);
     loop {
         std::thread::park();
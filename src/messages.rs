@@ -0,0 +1,71 @@
+//! Rendering of user-facing bot copy from operator-configurable templates,
+//! so phrasing can be changed (or localized) without recompiling.
+
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use tera::{Context, Tera};
+
+use crate::CONFIG;
+
+/// The built-in message templates, used for any key the operator's config
+/// doesn't override.
+fn default_templates() -> HashMap<String, String> {
+    [
+        (
+            "raffle_started",
+            "Raffle started{% if secret_code %} with secret code {{ secret_code }}{% endif %}",
+        ),
+        ("raffle_ended", "Horray! We gave out all the gift cards!"),
+        (
+            "raffle_empty",
+            "⚠️ That raffle has no participants or giftcards left - check you're using the right key, since nothing was ended.",
+        ),
+        (
+            "no_raffle",
+            "Sorry! There's no ongoing raffle at the moment. Watch out for future raffles in our user group!",
+        ),
+        (
+            "wrong_code",
+            "⛔ Incorrect secret code! Please provide the correct code to enter the raffle 🔑",
+        ),
+        (
+            "entered_raffle",
+            "🎉 Yay! You've been entered into the raffle! You are participant #{{ participant_count }}.",
+        ),
+        (
+            "won_giftcard",
+            "Congratulations! You won a giftcard 🎁 The code is: {{ giftcard }}",
+        ),
+        ("invalid_command", "⚠️ {{ reason }}"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+
+/// Merges operator-configured templates (from `Config::messages`) on top of
+/// [`default_templates`] and compiles them into a [`Tera`] instance.
+fn compile(overrides: &HashMap<String, String>) -> Tera {
+    let mut templates = default_templates();
+    templates.extend(overrides.clone());
+
+    let mut tera = Tera::default();
+    for (name, body) in &templates {
+        tera.add_raw_template(name, body)
+            .unwrap_or_else(|err| panic!("invalid message template {name:?}: {err:?}"));
+    }
+    tera
+}
+
+static TERA: Lazy<Tera> = Lazy::new(|| compile(&CONFIG.messages));
+
+/// Renders a configured message template by name. Falls back to a visible
+/// placeholder (rather than panicking) if the template is somehow missing,
+/// since this runs on the hot path of every reply.
+pub fn render(name: &str, ctx: &Context) -> String {
+    TERA.render(name, ctx).unwrap_or_else(|err| {
+        eprintln!("failed to render message template {name:?}: {err:?}");
+        format!("[[error rendering message {name}]]")
+    })
+}
@@ -0,0 +1,553 @@
+//! Storage backends for raffle state.
+//!
+//! `Storage` is the interface the rest of the bot talks to; today it has two
+//! implementations: [`AcidJsonStorage`], which keeps the original single
+//! JSON-file-backed `Store`, and [`SqliteStorage`], which keeps participants
+//! and giftcards in proper tables so entering/removing a participant is a
+//! single-row write rather than a full-document rewrite.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+    sync::Mutex,
+};
+
+use acidjson::AcidJson;
+use anyhow::Context;
+use rand::{seq::SliceRandom, thread_rng};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::RaffleKey;
+
+/// A giftcard bound to a specific winner, persisted before it's delivered so
+/// a crash between picking the winner and confirming delivery can never
+/// double-issue or lose a card.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PendingAward {
+    pub raffle_key: RaffleKey,
+    pub chat_id: i64,
+    pub giftcard: String,
+    pub delivered: bool,
+}
+
+/// The operations the bot needs from a raffle store, independent of how
+/// raffle state is actually persisted.
+pub trait Storage: Send + Sync {
+    fn add_giftcards(&self, raffle_key: &RaffleKey, giftcards: &[String]) -> anyhow::Result<()>;
+    fn pop_giftcard(&self, raffle_key: &RaffleKey) -> anyhow::Result<Option<String>>;
+    fn giftcard_count(&self, raffle_key: &RaffleKey) -> anyhow::Result<usize>;
+
+    fn set_secret_code(&self, raffle_key: &RaffleKey, code: Option<String>) -> anyhow::Result<()>;
+    /// Finds the key of the raffle whose secret code exactly equals `msg`
+    /// (after trimming), if exactly one raffle matches. Returns `None` both
+    /// when nothing matches and when more than one raffle shares a code, so
+    /// an entrant is never silently routed into the wrong raffle.
+    fn raffle_matching_code(&self, msg: &str) -> anyhow::Result<Option<RaffleKey>>;
+
+    /// Adds a participant to a raffle, returning the raffle's new
+    /// participant count.
+    fn add_participant(&self, raffle_key: &RaffleKey, chat_id: i64) -> anyhow::Result<usize>;
+    fn remove_participant(&self, raffle_key: &RaffleKey, chat_id: i64) -> anyhow::Result<()>;
+    fn list_participants(&self, raffle_key: &RaffleKey) -> anyhow::Result<Vec<i64>>;
+    fn participant_count(&self, raffle_key: &RaffleKey) -> anyhow::Result<usize>;
+
+    /// Whether any raffle currently has giftcards left to give out.
+    fn any_active_raffle(&self) -> anyhow::Result<bool>;
+    /// Wipes the participants, secret code, and any still-unpaired giftcards
+    /// of one raffle, leaving others untouched. Giftcards already bound to a
+    /// pending award are untouched, since `pair_awards` has already moved
+    /// them out of the raffle's giftcard pool. Clearing the secret code
+    /// keeps an ended raffle from still matching entrants while any other
+    /// raffle is active.
+    fn clear_raffle(&self, raffle_key: &RaffleKey) -> anyhow::Result<()>;
+
+    /// Atomically pairs every remaining participant of `raffle_key` with one
+    /// remaining giftcard, persisting the pairing as undelivered
+    /// `PendingAward`s and removing those participants/giftcards from the
+    /// raffle so a retry of this call won't re-pair them. Participants who
+    /// still hold an undelivered award for this raffle are skipped and keep
+    /// that award. Returns the awards just created.
+    fn pair_awards(&self, raffle_key: &RaffleKey) -> anyhow::Result<Vec<PendingAward>>;
+    /// Marks a pending award delivered once its giftcard message has been
+    /// sent successfully.
+    fn mark_delivered(&self, raffle_key: &RaffleKey, chat_id: i64) -> anyhow::Result<()>;
+    /// All undelivered awards for one raffle, including ones left over from
+    /// a previous crashed delivery attempt.
+    fn pending_awards(&self, raffle_key: &RaffleKey) -> anyhow::Result<Vec<PendingAward>>;
+    /// All undelivered awards across every raffle, used to resume delivery
+    /// on startup.
+    fn all_pending_awards(&self) -> anyhow::Result<Vec<PendingAward>>;
+}
+
+/// The mutable state of a single raffle, as kept by [`AcidJsonStorage`].
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Raffle {
+    pub giftcards: BTreeSet<String>,
+    pub participants: BTreeSet<i64>, // list of all chat ids
+    pub secret_code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct JsonStore {
+    pub raffles: BTreeMap<RaffleKey, Raffle>,
+    pub pending_awards: Vec<PendingAward>,
+}
+
+/// The original storage backend: the whole document lives in one
+/// `AcidJson`-backed file and is rewritten on every write.
+pub struct AcidJsonStorage(AcidJson<JsonStore>);
+
+impl AcidJsonStorage {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self(AcidJson::open_or_else(path, JsonStore::default)?))
+    }
+}
+
+impl Storage for AcidJsonStorage {
+    fn add_giftcards(&self, raffle_key: &RaffleKey, giftcards: &[String]) -> anyhow::Result<()> {
+        let mut store = self.0.write();
+        let raffle = store.raffles.entry(raffle_key.clone()).or_default();
+        raffle.giftcards.extend(giftcards.iter().cloned());
+        Ok(())
+    }
+
+    fn pop_giftcard(&self, raffle_key: &RaffleKey) -> anyhow::Result<Option<String>> {
+        let mut store = self.0.write();
+        Ok(store
+            .raffles
+            .get_mut(raffle_key)
+            .and_then(|r| r.giftcards.pop_first()))
+    }
+
+    fn giftcard_count(&self, raffle_key: &RaffleKey) -> anyhow::Result<usize> {
+        Ok(self
+            .0
+            .read()
+            .raffles
+            .get(raffle_key)
+            .map(|r| r.giftcards.len())
+            .unwrap_or(0))
+    }
+
+    fn set_secret_code(&self, raffle_key: &RaffleKey, code: Option<String>) -> anyhow::Result<()> {
+        self.0
+            .write()
+            .raffles
+            .entry(raffle_key.clone())
+            .or_default()
+            .secret_code = code;
+        Ok(())
+    }
+
+    fn raffle_matching_code(&self, msg: &str) -> anyhow::Result<Option<RaffleKey>> {
+        let msg = msg.trim();
+        let mut matches = self.0.read().raffles.iter().filter_map(|(key, raffle)| {
+            let code = raffle.secret_code.as_ref()?;
+            (code.as_str() == msg).then(|| key.clone())
+        });
+        let first = matches.next();
+        Ok(if matches.next().is_none() { first } else { None })
+    }
+
+    fn add_participant(&self, raffle_key: &RaffleKey, chat_id: i64) -> anyhow::Result<usize> {
+        let mut store = self.0.write();
+        let raffle = store.raffles.entry(raffle_key.clone()).or_default();
+        raffle.participants.insert(chat_id);
+        Ok(raffle.participants.len())
+    }
+
+    fn remove_participant(&self, raffle_key: &RaffleKey, chat_id: i64) -> anyhow::Result<()> {
+        if let Some(raffle) = self.0.write().raffles.get_mut(raffle_key) {
+            raffle.participants.remove(&chat_id);
+        }
+        Ok(())
+    }
+
+    fn list_participants(&self, raffle_key: &RaffleKey) -> anyhow::Result<Vec<i64>> {
+        Ok(self
+            .0
+            .read()
+            .raffles
+            .get(raffle_key)
+            .map(|r| r.participants.iter().copied().collect())
+            .unwrap_or_default())
+    }
+
+    fn participant_count(&self, raffle_key: &RaffleKey) -> anyhow::Result<usize> {
+        Ok(self
+            .0
+            .read()
+            .raffles
+            .get(raffle_key)
+            .map(|r| r.participants.len())
+            .unwrap_or(0))
+    }
+
+    fn any_active_raffle(&self) -> anyhow::Result<bool> {
+        Ok(self.0.read().raffles.values().any(|r| !r.giftcards.is_empty()))
+    }
+
+    fn clear_raffle(&self, raffle_key: &RaffleKey) -> anyhow::Result<()> {
+        let mut store = self.0.write();
+        if let Some(raffle) = store.raffles.get_mut(raffle_key) {
+            raffle.participants.clear();
+            raffle.giftcards.clear();
+            raffle.secret_code = None;
+        }
+        // Drop delivered awards for this key so a reused key's next
+        // `pair_awards` isn't blocked by a stale winner record.
+        store
+            .pending_awards
+            .retain(|a| !(&a.raffle_key == raffle_key && a.delivered));
+        Ok(())
+    }
+
+    fn pair_awards(&self, raffle_key: &RaffleKey) -> anyhow::Result<Vec<PendingAward>> {
+        let mut store = self.0.write();
+        let already_awarded: BTreeSet<i64> = store
+            .pending_awards
+            .iter()
+            .filter(|a| &a.raffle_key == raffle_key && !a.delivered)
+            .map(|a| a.chat_id)
+            .collect();
+        let Some(raffle) = store.raffles.get_mut(raffle_key) else {
+            return Ok(Vec::new());
+        };
+        let mut participants: Vec<i64> = raffle.participants.iter().copied().collect();
+        participants.shuffle(&mut thread_rng());
+
+        let mut awards = Vec::new();
+        for chat_id in participants {
+            if already_awarded.contains(&chat_id) {
+                continue;
+            }
+            let Some(giftcard) = raffle.giftcards.pop_first() else {
+                break;
+            };
+            raffle.participants.remove(&chat_id);
+            awards.push(PendingAward {
+                raffle_key: raffle_key.clone(),
+                chat_id,
+                giftcard,
+                delivered: false,
+            });
+        }
+        store.pending_awards.extend(awards.iter().cloned());
+        Ok(awards)
+    }
+
+    fn mark_delivered(&self, raffle_key: &RaffleKey, chat_id: i64) -> anyhow::Result<()> {
+        let mut store = self.0.write();
+        for award in store.pending_awards.iter_mut() {
+            if &award.raffle_key == raffle_key && award.chat_id == chat_id && !award.delivered {
+                award.delivered = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn pending_awards(&self, raffle_key: &RaffleKey) -> anyhow::Result<Vec<PendingAward>> {
+        Ok(self
+            .0
+            .read()
+            .pending_awards
+            .iter()
+            .filter(|a| &a.raffle_key == raffle_key && !a.delivered)
+            .cloned()
+            .collect())
+    }
+
+    fn all_pending_awards(&self) -> anyhow::Result<Vec<PendingAward>> {
+        Ok(self
+            .0
+            .read()
+            .pending_awards
+            .iter()
+            .filter(|a| !a.delivered)
+            .cloned()
+            .collect())
+    }
+}
+
+/// A SQLite-backed storage implementation: participants and giftcards each
+/// get their own table, so entering or removing one participant is a
+/// single-row operation, and the bot survives restarts mid-giveaway without
+/// rereading the whole state into memory.
+pub struct SqliteStorage(Mutex<Connection>);
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path).context("opening sqlite store")?;
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS participants (
+                raffle_key TEXT NOT NULL,
+                chat_id    INTEGER NOT NULL,
+                PRIMARY KEY (raffle_key, chat_id)
+            );
+            CREATE TABLE IF NOT EXISTS giftcards (
+                raffle_key TEXT NOT NULL,
+                code       TEXT NOT NULL,
+                PRIMARY KEY (raffle_key, code)
+            );
+            CREATE TABLE IF NOT EXISTS secret_codes (
+                raffle_key TEXT PRIMARY KEY,
+                code       TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS pending_awards (
+                raffle_key TEXT NOT NULL,
+                chat_id    INTEGER NOT NULL,
+                giftcard   TEXT NOT NULL,
+                delivered  INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (raffle_key, chat_id)
+            );
+            ",
+        )
+        .context("creating sqlite schema")?;
+        Ok(Self(Mutex::new(conn)))
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.0.lock().expect("sqlite connection mutex poisoned")
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn add_giftcards(&self, raffle_key: &RaffleKey, giftcards: &[String]) -> anyhow::Result<()> {
+        let conn = self.conn();
+        for code in giftcards {
+            conn.execute(
+                "INSERT OR IGNORE INTO giftcards (raffle_key, code) VALUES (?1, ?2)",
+                (raffle_key, code),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn pop_giftcard(&self, raffle_key: &RaffleKey) -> anyhow::Result<Option<String>> {
+        let conn = self.conn();
+        let code: Option<String> = conn
+            .query_row(
+                "SELECT code FROM giftcards WHERE raffle_key = ?1 ORDER BY code LIMIT 1",
+                [raffle_key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(code) = &code {
+            conn.execute(
+                "DELETE FROM giftcards WHERE raffle_key = ?1 AND code = ?2",
+                (raffle_key, code),
+            )?;
+        }
+        Ok(code)
+    }
+
+    fn giftcard_count(&self, raffle_key: &RaffleKey) -> anyhow::Result<usize> {
+        let count: i64 = self.conn().query_row(
+            "SELECT COUNT(*) FROM giftcards WHERE raffle_key = ?1",
+            [raffle_key],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn set_secret_code(&self, raffle_key: &RaffleKey, code: Option<String>) -> anyhow::Result<()> {
+        let conn = self.conn();
+        match code {
+            Some(code) => conn.execute(
+                "INSERT INTO secret_codes (raffle_key, code) VALUES (?1, ?2)
+                 ON CONFLICT (raffle_key) DO UPDATE SET code = excluded.code",
+                (raffle_key, code),
+            )?,
+            None => conn.execute("DELETE FROM secret_codes WHERE raffle_key = ?1", [raffle_key])?,
+        };
+        Ok(())
+    }
+
+    fn raffle_matching_code(&self, msg: &str) -> anyhow::Result<Option<RaffleKey>> {
+        let msg = msg.trim();
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT raffle_key FROM secret_codes WHERE code = ?1")?;
+        let mut matches = stmt
+            .query_map([msg], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter();
+        let first = matches.next();
+        Ok(if matches.next().is_none() { first } else { None })
+    }
+
+    fn add_participant(&self, raffle_key: &RaffleKey, chat_id: i64) -> anyhow::Result<usize> {
+        let conn = self.conn();
+        conn.execute(
+            "INSERT OR IGNORE INTO participants (raffle_key, chat_id) VALUES (?1, ?2)",
+            (raffle_key, chat_id),
+        )?;
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM participants WHERE raffle_key = ?1",
+            [raffle_key],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn remove_participant(&self, raffle_key: &RaffleKey, chat_id: i64) -> anyhow::Result<()> {
+        self.conn().execute(
+            "DELETE FROM participants WHERE raffle_key = ?1 AND chat_id = ?2",
+            (raffle_key, chat_id),
+        )?;
+        Ok(())
+    }
+
+    fn list_participants(&self, raffle_key: &RaffleKey) -> anyhow::Result<Vec<i64>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT chat_id FROM participants WHERE raffle_key = ?1")?;
+        let ids = stmt
+            .query_map([raffle_key], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?;
+        Ok(ids)
+    }
+
+    fn participant_count(&self, raffle_key: &RaffleKey) -> anyhow::Result<usize> {
+        let count: i64 = self.conn().query_row(
+            "SELECT COUNT(*) FROM participants WHERE raffle_key = ?1",
+            [raffle_key],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn any_active_raffle(&self) -> anyhow::Result<bool> {
+        let count: i64 = self
+            .conn()
+            .query_row("SELECT COUNT(*) FROM giftcards", [], |row| row.get(0))?;
+        Ok(count > 0)
+    }
+
+    fn clear_raffle(&self, raffle_key: &RaffleKey) -> anyhow::Result<()> {
+        let conn = self.conn();
+        conn.execute("DELETE FROM participants WHERE raffle_key = ?1", [raffle_key])?;
+        conn.execute("DELETE FROM giftcards WHERE raffle_key = ?1", [raffle_key])?;
+        conn.execute("DELETE FROM secret_codes WHERE raffle_key = ?1", [raffle_key])?;
+        conn.execute(
+            "DELETE FROM pending_awards WHERE raffle_key = ?1 AND delivered = 1",
+            [raffle_key],
+        )?;
+        Ok(())
+    }
+
+    fn pair_awards(&self, raffle_key: &RaffleKey) -> anyhow::Result<Vec<PendingAward>> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+
+        let mut participants: Vec<i64> = {
+            let mut stmt = tx.prepare("SELECT chat_id FROM participants WHERE raffle_key = ?1")?;
+            stmt.query_map([raffle_key], |row| row.get(0))?
+                .collect::<Result<Vec<i64>, _>>()?
+        };
+        participants.shuffle(&mut thread_rng());
+
+        let mut awards = Vec::new();
+        for chat_id in participants {
+            let already_awarded: bool = tx.query_row(
+                "SELECT EXISTS (SELECT 1 FROM pending_awards
+                 WHERE raffle_key = ?1 AND chat_id = ?2 AND delivered = 0)",
+                (raffle_key, chat_id),
+                |row| row.get(0),
+            )?;
+            if already_awarded {
+                continue;
+            }
+            let giftcard: Option<String> = tx
+                .query_row(
+                    "SELECT code FROM giftcards WHERE raffle_key = ?1 ORDER BY code LIMIT 1",
+                    [raffle_key],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            let Some(giftcard) = giftcard else { break };
+
+            tx.execute(
+                "DELETE FROM giftcards WHERE raffle_key = ?1 AND code = ?2",
+                (raffle_key, &giftcard),
+            )?;
+            tx.execute(
+                "DELETE FROM participants WHERE raffle_key = ?1 AND chat_id = ?2",
+                (raffle_key, chat_id),
+            )?;
+            // `OR REPLACE`, not a plain INSERT: holders of an undelivered
+            // award were skipped above, so a conflicting row here is a
+            // delivered award that `clear_raffle` hasn't dropped yet, and
+            // the new award replaces it rather than failing the whole round.
+            tx.execute(
+                "INSERT OR REPLACE INTO pending_awards (raffle_key, chat_id, giftcard, delivered)
+                 VALUES (?1, ?2, ?3, 0)",
+                (raffle_key, chat_id, &giftcard),
+            )?;
+            awards.push(PendingAward {
+                raffle_key: raffle_key.clone(),
+                chat_id,
+                giftcard,
+                delivered: false,
+            });
+        }
+        tx.commit()?;
+        Ok(awards)
+    }
+
+    fn mark_delivered(&self, raffle_key: &RaffleKey, chat_id: i64) -> anyhow::Result<()> {
+        self.conn().execute(
+            "UPDATE pending_awards SET delivered = 1 WHERE raffle_key = ?1 AND chat_id = ?2",
+            (raffle_key, chat_id),
+        )?;
+        Ok(())
+    }
+
+    fn pending_awards(&self, raffle_key: &RaffleKey) -> anyhow::Result<Vec<PendingAward>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT raffle_key, chat_id, giftcard, delivered FROM pending_awards
+             WHERE raffle_key = ?1 AND delivered = 0",
+        )?;
+        let awards = stmt
+            .query_map([raffle_key], row_to_pending_award)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(awards)
+    }
+
+    fn all_pending_awards(&self) -> anyhow::Result<Vec<PendingAward>> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT raffle_key, chat_id, giftcard, delivered FROM pending_awards WHERE delivered = 0",
+        )?;
+        let awards = stmt
+            .query_map([], row_to_pending_award)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(awards)
+    }
+}
+
+fn row_to_pending_award(row: &rusqlite::Row) -> rusqlite::Result<PendingAward> {
+    Ok(PendingAward {
+        raffle_key: row.get(0)?,
+        chat_id: row.get(1)?,
+        giftcard: row.get(2)?,
+        delivered: row.get::<_, i64>(3)? != 0,
+    })
+}
+
+/// Which storage backend to use, selected via `Config::store_backend`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreBackend {
+    #[default]
+    AcidJson,
+    Sqlite,
+}
+
+pub fn open(backend: StoreBackend, path: &Path) -> anyhow::Result<Box<dyn Storage>> {
+    Ok(match backend {
+        StoreBackend::AcidJson => Box::new(AcidJsonStorage::open(path)?),
+        StoreBackend::Sqlite => Box::new(SqliteStorage::open(path)?),
+    })
+}